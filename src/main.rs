@@ -1,17 +1,552 @@
 extern crate dotenv;
 use anyhow::*;
 use dotenv::dotenv;
+use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver};
 use esp_idf_hal::peripherals::Peripherals;
 use esp_idf_hal::units::FromValueType;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::nvs::EspDefaultNvsPartition;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
+use esp_idf_svc::http::server::{Configuration as HttpServerConfiguration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read as _, Write as _};
+use esp_idf_svc::ipv4::{ClientConfiguration as Ipv4ClientConfiguration, ClientSettings, Configuration as Ipv4Configuration, Mask, Subnet};
+use esp_idf_svc::mqtt::client::{EspMqttClient, EspMqttConnection, MqttClientConfiguration, QoS};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi, WifiDriver,
+};
+use serde::{Deserialize, Serialize};
 use heapless::String;
 use std::env;
-use std::net::UdpSocket;
+use std::net::Ipv4Addr;
 use std::result::Result::Ok;
 use std::str::FromStr;
+use std::sync::mpsc;
+
+/// A command parsed from the legacy `TOGGLE`/`0-180` wire format or the JSON
+/// payload accepted on `devices/<id>/cmd`, or from the HTTP `/set` query string.
+enum Command {
+    Toggle,
+    Angle(u8),
+    Explicit { r: u8, g: u8, b: u8, servo: Option<u8> },
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonCommand {
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    servo: Option<u8>,
+}
+
+#[derive(Debug, Serialize)]
+struct StatePayload {
+    /// `None` once an explicit RGB command has moved the light off the
+    /// palette, so consumers don't mistake a stale index for the color
+    /// actually showing.
+    color_index: Option<u32>,
+    servo_angle: u8,
+}
+
+/// Duration of a color/servo fade, in milliseconds.
+const FADE_DURATION_MS: u32 = 400;
+/// Interval between duty updates while fading.
+const FADE_STEP_MS: u32 = 20;
+
+/// Served at `GET /`: an RGB color picker and servo slider that send a GET to
+/// `/set` with a plain query string so no JS framework or build step is needed.
+const CONTROL_PAGE_HTML: &str = r##"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>rs-power-trigger</title></head>
+<body>
+<h1>rs-power-trigger control</h1>
+<form id="controls">
+  <label>Color <input type="color" id="color" value="#ff0000"></label><br>
+  <label>Servo <input type="range" id="servo" min="0" max="180" value="90"></label>
+</form>
+<script>
+const color = document.getElementById('color');
+const servo = document.getElementById('servo');
+function send() {
+  const hex = color.value;
+  const r = parseInt(hex.substr(1, 2), 16);
+  const g = parseInt(hex.substr(3, 2), 16);
+  const b = parseInt(hex.substr(5, 2), 16);
+  fetch(`/set?r=${r}&g=${g}&b=${b}&servo=${servo.value}`);
+}
+color.addEventListener('input', send);
+servo.addEventListener('change', send);
+</script>
+</body>
+</html>"##;
+
+/// NVS namespace the provisioned Wi-Fi credentials live under.
+const WIFI_NVS_NAMESPACE: &str = "wifi_cfg";
+const NVS_KEY_SSID: &str = "ssid";
+const NVS_KEY_PASSWORD: &str = "password";
+/// How many times to retry `wifi.connect()` before falling back to provisioning.
+const WIFI_CONNECT_RETRIES: u32 = 5;
+/// SSID of the provisioning access point the device exposes when it has no
+/// working Wi-Fi credentials.
+const PROVISIONING_AP_SSID: &str = "rs-power-trigger-setup";
+
+const PROVISIONING_PAGE_HEADER: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>rs-power-trigger setup</title></head>
+<body>
+<h1>Wi-Fi setup</h1>
+<form method="POST" action="/provision">
+<label>Network:
+<select name="ssid">
+"#;
+
+const PROVISIONING_PAGE_FOOTER: &str = r#"</select>
+</label><br>
+<label>Password: <input type="password" name="password"></label><br>
+<button type="submit">Connect</button>
+</form>
+</body>
+</html>"#;
+
+/// Reads previously provisioned Wi-Fi credentials out of NVS, if any.
+fn load_wifi_credentials(
+    nvs: &EspDefaultNvsPartition,
+) -> anyhow::Result<Option<(String<32>, String<64>)>> {
+    let storage = EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true)?;
+
+    // `get_str` wants the buffer to fit the trailing NUL on top of the string
+    // itself, so size these one past the `String<32>`/`String<64>` capacity;
+    // otherwise a stored value at exactly that capacity would error out here
+    // rather than falling through to the corrupt-data handling below.
+    let mut ssid_buf = [0u8; 33];
+    let mut password_buf = [0u8; 65];
+    let ssid = storage.get_str(NVS_KEY_SSID, &mut ssid_buf).unwrap_or(None);
+    let password = storage.get_str(NVS_KEY_PASSWORD, &mut password_buf).unwrap_or(None);
+
+    match (ssid, password) {
+        (Some(ssid), Some(password)) => match (ssid.parse::<String<32>>(), password.parse::<String<64>>()) {
+            (Ok(ssid), Ok(password)) => Ok(Some((ssid, password))),
+            _ => {
+                log::warn!("Stored Wi-Fi credentials are corrupt or oversized; ignoring");
+                Ok(None)
+            }
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Saves Wi-Fi credentials to NVS so they survive reboots.
+fn save_wifi_credentials(nvs: &EspDefaultNvsPartition, ssid: &str, password: &str) -> anyhow::Result<()> {
+    let mut storage: EspNvs<NvsDefault> = EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true)?;
+    storage.set_str(NVS_KEY_SSID, ssid)?;
+    storage.set_str(NVS_KEY_PASSWORD, password)?;
+    Ok(())
+}
+
+/// Falls back to the compile-time `.env` credentials that predate NVS
+/// provisioning, so existing deployments keep working untouched.
+fn legacy_env_credentials() -> Option<(String<32>, String<64>)> {
+    let ssid = env::var("WIFI_SSID").ok()?.parse::<String<32>>().ok()?;
+    let password = env::var("WIFI_PASSWORD").ok()?.parse::<String<64>>().ok()?;
+    Some((ssid, password))
+}
+
+/// Switches the radio to AP mode and serves a small HTTP form that scans for
+/// visible networks, lets the user pick one and enter a password, persists
+/// the result to NVS, then reboots into STA mode with the new credentials.
+fn run_provisioning_portal(
+    wifi: &mut BlockingWifi<EspWifi<'static>>,
+    nvs: &EspDefaultNvsPartition,
+) -> anyhow::Result<(String<32>, String<64>)> {
+    // Stop any previously-started STA/AP session before switching modes; wifi.start()
+    // on an already-started driver errors out.
+    wifi.stop().ok();
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: PROVISIONING_AP_SSID.parse().unwrap(),
+        // Open network: this AP only exists so a phone/laptop can reach the
+        // provisioning form, and `Default` would otherwise pair WPA2Personal
+        // with an empty password, which esp_wifi rejects outright.
+        auth_method: AuthMethod::None,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+
+    let networks = wifi.scan().unwrap_or_default();
+    let mut page = std::string::String::from(PROVISIONING_PAGE_HEADER);
+    for network in &networks {
+        page.push_str(&format!("<option value=\"{0}\">{0}</option>\n", network.ssid));
+    }
+    page.push_str(PROVISIONING_PAGE_FOOTER);
+
+    let (tx, rx) = mpsc::channel::<(std::string::String, std::string::String)>();
+    let mut server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+    server.fn_handler("/", Method::Get, move |request| {
+        let mut response = request.into_ok_response()?;
+        response.write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+    server.fn_handler("/provision", Method::Post, move |mut request| {
+        const MAX_FORM_BODY: usize = 512;
+
+        let content_len = request.header("Content-Length").and_then(|v| v.parse::<usize>().ok());
+        if content_len.is_some_and(|len| len > MAX_FORM_BODY) {
+            request
+                .into_response(400, Some("Bad Request"), &[])?
+                .write_all(b"form body too large")?;
+            return Ok(());
+        }
+
+        let mut body = std::vec::Vec::new();
+        let mut chunk = [0u8; 128];
+        loop {
+            let n = request.read(&mut chunk)?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..n]);
+            if body.len() > MAX_FORM_BODY {
+                request
+                    .into_response(400, Some("Bad Request"), &[])?
+                    .write_all(b"form body too large")?;
+                return Ok(());
+            }
+            if content_len.is_some_and(|len| body.len() >= len) {
+                break;
+            }
+        }
+        if content_len.is_some_and(|len| body.len() < len) {
+            request
+                .into_response(400, Some("Bad Request"), &[])?
+                .write_all(b"form body truncated")?;
+            return Ok(());
+        }
+
+        let form = std::str::from_utf8(&body).unwrap_or("");
+        let mut ssid = std::string::String::new();
+        let mut password = std::string::String::new();
+        for pair in form.split('&') {
+            if let Some((key, value)) = pair.split_once('=') {
+                match key {
+                    "ssid" => ssid = urlencoding_decode(value),
+                    "password" => password = urlencoding_decode(value),
+                    _ => {}
+                }
+            }
+        }
+        tx.send((ssid, password)).ok();
+        request
+            .into_ok_response()?
+            .write_all(b"Saved. Rebooting into station mode...")?;
+        Ok(())
+    })?;
+
+    let ap_ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Provisioning AP '{PROVISIONING_AP_SSID}' is up; connect and visit http://{ap_ip}/");
+    let (ssid, password) = rx.recv()?;
+    drop(server);
+    wifi.stop()?;
+
+    let ssid = ssid.parse::<String<32>>().map_err(|_| anyhow!("SSID too long"))?;
+    let password = password
+        .parse::<String<64>>()
+        .map_err(|_| anyhow!("password too long"))?;
+
+    save_wifi_credentials(nvs, &ssid, &password)?;
+
+    log::info!("Wi-Fi credentials saved; rebooting into station mode");
+    unsafe { esp_idf_sys::esp_restart() };
+
+    Ok((ssid, password))
+}
+
+const NVS_KEY_STATIC_IP: &str = "static_ip";
+const NVS_KEY_GATEWAY_IP: &str = "gateway_ip";
+const NVS_KEY_SUBNET_MASK: &str = "subnet_mask";
+
+/// Subnet prefix length used when `SUBNET_MASK`/`NETMASK` isn't set.
+const DEFAULT_SUBNET_MASK: u8 = 24;
+
+/// A fixed IPv4 address/gateway/mask for the STA netif, used in place of DHCP.
+struct StaticIpConfig {
+    ip: Ipv4Addr,
+    gateway: Ipv4Addr,
+    mask: u8,
+}
+
+/// Parses a CIDR prefix length (e.g. `"24"`) out of an optional raw
+/// `SUBNET_MASK`/`NETMASK` value, warning when a value was given but didn't
+/// parse, or parsed outside the valid 0-32 CIDR range (e.g. a dotted-decimal
+/// mask like `255.255.255.0`, or a nonsensical value like `99`), rather than
+/// silently falling back as if it were unset.
+fn parse_subnet_mask(raw: Option<std::string::String>) -> u8 {
+    match raw {
+        Some(raw) => match raw.parse::<u8>() {
+            Ok(mask) if mask <= 32 => mask,
+            _ => {
+                log::warn!(
+                    "SUBNET_MASK/NETMASK value '{raw}' is not a valid CIDR prefix (0-32); \
+                     falling back to /{DEFAULT_SUBNET_MASK}"
+                );
+                DEFAULT_SUBNET_MASK
+            }
+        },
+        None => DEFAULT_SUBNET_MASK,
+    }
+}
+
+/// Reads `STATIC_IP`/`GATEWAY_IP` (and optionally `SUBNET_MASK`) from the
+/// environment, falling back to the same keys in the `wifi_cfg` NVS
+/// namespace. The NVS fallback is manufacturing-time-only: nothing in the
+/// provisioning portal writes these keys (it only collects SSID/password),
+/// so they're reachable only by flashing NVS out of band, e.g. via
+/// `idf.py nvs_partition_gen` or a factory-provisioning script. Returns
+/// `None` (DHCP) unless both an address and a gateway are available; the
+/// mask defaults to `DEFAULT_SUBNET_MASK` when unset.
+fn load_static_ip_config(nvs: &EspDefaultNvsPartition) -> Option<StaticIpConfig> {
+    if let (Ok(ip), Ok(gateway)) = (env::var("STATIC_IP"), env::var("GATEWAY_IP")) {
+        if let (Ok(ip), Ok(gateway)) = (ip.parse(), gateway.parse()) {
+            let mask = parse_subnet_mask(
+                env::var("SUBNET_MASK").or_else(|_| env::var("NETMASK")).ok(),
+            );
+            return Some(StaticIpConfig { ip, gateway, mask });
+        }
+    }
+
+    let storage = EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true).ok()?;
+    let mut ip_buf = [0u8; 16];
+    let mut gateway_buf = [0u8; 16];
+    let mut mask_buf = [0u8; 4];
+    let ip = storage.get_str(NVS_KEY_STATIC_IP, &mut ip_buf).ok()??;
+    let gateway = storage.get_str(NVS_KEY_GATEWAY_IP, &mut gateway_buf).ok()??;
+    let mask = parse_subnet_mask(
+        storage
+            .get_str(NVS_KEY_SUBNET_MASK, &mut mask_buf)
+            .ok()
+            .flatten()
+            .map(str::to_owned),
+    );
+    Some(StaticIpConfig {
+        ip: ip.parse().ok()?,
+        gateway: gateway.parse().ok()?,
+        mask,
+    })
+}
+
+/// Builds the STA netif, fixed to `static_ip` when given or DHCP otherwise.
+fn build_sta_netif(static_ip: Option<StaticIpConfig>) -> anyhow::Result<EspNetif> {
+    let mut netif_conf = NetifConfiguration::wifi_default_client();
+    if let Some(cfg) = static_ip {
+        netif_conf.ip_configuration = Ipv4Configuration::Client(Ipv4ClientConfiguration::Fixed(ClientSettings {
+            ip: cfg.ip,
+            subnet: Subnet {
+                gateway: cfg.gateway,
+                mask: Mask(cfg.mask),
+            },
+            dns: None,
+            secondary_dns: None,
+        }));
+        log::info!("Using static IP {}/{} via gateway {}", cfg.ip, cfg.mask, cfg.gateway);
+    }
+    Ok(EspNetif::new_with_conf(&netif_conf)?)
+}
+
+/// Retries `wifi.connect()` up to `WIFI_CONNECT_RETRIES` times, returning
+/// whether the connection ultimately succeeded.
+fn connect_with_retries(wifi: &mut BlockingWifi<EspWifi<'static>>) -> bool {
+    for attempt in 1..=WIFI_CONNECT_RETRIES {
+        match wifi.connect() {
+            Ok(()) => return true,
+            Err(e) => {
+                log::warn!("Wi-Fi connect attempt {attempt}/{WIFI_CONNECT_RETRIES} failed: {e:?}");
+            }
+        }
+    }
+    false
+}
+
+/// Credentials for a WPA2-Enterprise (EAP-PEAP/TTLS) network such as eduroam.
+struct EapCredentials {
+    identity: std::string::String,
+    anonymous_identity: Option<std::string::String>,
+    username: std::string::String,
+    password: std::string::String,
+}
+
+/// Whether the enterprise (EAP) Wi-Fi path should be used instead of the
+/// default WPA2-PSK path. Opt-in via `WIFI_ENTERPRISE=true`.
+fn wifi_enterprise_enabled() -> bool {
+    env::var("WIFI_ENTERPRISE")
+        .map(|v| v.eq_ignore_ascii_case("true") || v == "1")
+        .unwrap_or(false)
+}
+
+const NVS_KEY_EAP_IDENTITY: &str = "eap_identity";
+const NVS_KEY_EAP_ANON_IDENTITY: &str = "eap_anon_id";
+const NVS_KEY_EAP_USERNAME: &str = "eap_username";
+const NVS_KEY_EAP_PASSWORD: &str = "eap_password";
+
+/// Reads EAP identity/username/password from the environment, falling back to
+/// the same keys in the `wifi_cfg` NVS namespace. As with `load_static_ip_config`,
+/// the NVS fallback is manufacturing-time-only — the provisioning portal has no
+/// EAP form, so these keys are only reachable by writing NVS out of band.
+fn load_eap_credentials(nvs: &EspDefaultNvsPartition) -> Option<EapCredentials> {
+    if let (Ok(identity), Ok(username), Ok(password)) =
+        (env::var("EAP_IDENTITY"), env::var("EAP_USERNAME"), env::var("EAP_PASSWORD"))
+    {
+        return Some(EapCredentials {
+            identity,
+            anonymous_identity: env::var("EAP_ANONYMOUS_IDENTITY").ok(),
+            username,
+            password,
+        });
+    }
+
+    let storage = EspNvs::new(nvs.clone(), WIFI_NVS_NAMESPACE, true).ok()?;
+    let mut identity_buf = [0u8; 128];
+    let mut anonymous_identity_buf = [0u8; 128];
+    let mut username_buf = [0u8; 128];
+    let mut password_buf = [0u8; 64];
+    let identity = storage.get_str(NVS_KEY_EAP_IDENTITY, &mut identity_buf).ok()??;
+    let anonymous_identity = storage
+        .get_str(NVS_KEY_EAP_ANON_IDENTITY, &mut anonymous_identity_buf)
+        .ok()
+        .flatten()
+        .map(str::to_string);
+    let username = storage.get_str(NVS_KEY_EAP_USERNAME, &mut username_buf).ok()??;
+    let password = storage.get_str(NVS_KEY_EAP_PASSWORD, &mut password_buf).ok()??;
+
+    Some(EapCredentials {
+        identity: identity.to_string(),
+        anonymous_identity,
+        username: username.to_string(),
+        password: password.to_string(),
+    })
+}
+
+/// Configures the EAP-PEAP/TTLS client and enables WPA2-Enterprise mode.
+/// `esp-idf-svc` doesn't expose a safe wrapper for this, so it goes straight
+/// through the generated `esp-idf-sys` bindings, as ESP-IDF's own enterprise
+/// examples do.
+fn configure_eap(eap: &EapCredentials) -> anyhow::Result<()> {
+    unsafe {
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_identity(
+            eap.identity.as_ptr(),
+            eap.identity.len() as i32
+        ))?;
+        if let Some(anonymous_identity) = &eap.anonymous_identity {
+            esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_anonymous_identity(
+                anonymous_identity.as_ptr(),
+                anonymous_identity.len() as i32
+            ))?;
+        }
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_username(
+            eap.username.as_ptr(),
+            eap.username.len() as i32
+        ))?;
+        esp_idf_sys::esp!(esp_idf_sys::esp_eap_client_set_password(
+            eap.password.as_ptr(),
+            eap.password.len() as i32
+        ))?;
+        esp_idf_sys::esp!(esp_idf_sys::esp_wifi_sta_enterprise_enable())?;
+    }
+    Ok(())
+}
+
+/// Minimal `application/x-www-form-urlencoded` decoder for the SSID/password
+/// form fields. Percent-escapes are decoded byte-by-byte into a buffer and
+/// reassembled with `from_utf8_lossy`, rather than pushed as individual
+/// `char`s, so a multi-byte UTF-8 sequence (e.g. an accented character in an
+/// SSID) survives intact instead of being split into mojibake.
+fn urlencoding_decode(input: &str) -> std::string::String {
+    let bytes = input.as_bytes();
+    let mut out = std::vec::Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => {
+                out.push(b' ');
+                i += 1;
+            }
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 3;
+                    }
+                    Err(_) => {
+                        out.push(bytes[i]);
+                        i += 1;
+                    }
+                }
+            }
+            byte => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    std::string::String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Tracks the duty currently being driven on each channel so fades can
+/// interpolate from wherever the hardware actually is, not just the last
+/// commanded target.
+#[derive(Clone, Copy)]
+struct ChannelState {
+    red: u32,
+    green: u32,
+    blue: u32,
+    servo: u32,
+}
+
+const DEVICE_STATE_NVS_NAMESPACE: &str = "device_state";
+const NVS_KEY_COLOR_INDEX: &str = "color_index";
+const NVS_KEY_COLOR_VALID: &str = "color_valid";
+const NVS_KEY_ANGLE: &str = "angle";
+const NVS_KEY_RED: &str = "red";
+const NVS_KEY_GREEN: &str = "green";
+const NVS_KEY_BLUE: &str = "blue";
+const NVS_KEY_SERVO: &str = "servo";
+
+/// Restores the color index, whether it's still valid (vs. overridden by an
+/// explicit RGB command), last RGB duty and servo angle from a previous
+/// session, so a power cycle doesn't reset the device to its defaults.
+fn load_device_state(nvs: &EspDefaultNvsPartition) -> Option<(u32, bool, ChannelState, u8)> {
+    let storage = EspNvs::new(nvs.clone(), DEVICE_STATE_NVS_NAMESPACE, true).ok()?;
+    let color_index = storage.get_u32(NVS_KEY_COLOR_INDEX).ok()??;
+    let color_index_valid = storage.get_u8(NVS_KEY_COLOR_VALID).ok()?? != 0;
+    let angle = storage.get_u8(NVS_KEY_ANGLE).ok()??;
+    let state = ChannelState {
+        red: storage.get_u32(NVS_KEY_RED).ok()??,
+        green: storage.get_u32(NVS_KEY_GREEN).ok()??,
+        blue: storage.get_u32(NVS_KEY_BLUE).ok()??,
+        servo: storage.get_u32(NVS_KEY_SERVO).ok()??,
+    };
+    Some((color_index, color_index_valid, state, angle))
+}
+
+/// Persists the current color index, whether it's still valid, channel
+/// duties and servo angle so they can be restored on the next boot.
+fn save_device_state(
+    nvs: &EspDefaultNvsPartition,
+    color_index: u32,
+    color_index_valid: bool,
+    state: &ChannelState,
+    angle: u8,
+) -> anyhow::Result<()> {
+    let mut storage: EspNvs<NvsDefault> = EspNvs::new(nvs.clone(), DEVICE_STATE_NVS_NAMESPACE, true)?;
+    storage.set_u32(NVS_KEY_COLOR_INDEX, color_index)?;
+    storage.set_u8(NVS_KEY_COLOR_VALID, color_index_valid as u8)?;
+    storage.set_u8(NVS_KEY_ANGLE, angle)?;
+    storage.set_u32(NVS_KEY_RED, state.red)?;
+    storage.set_u32(NVS_KEY_GREEN, state.green)?;
+    storage.set_u32(NVS_KEY_BLUE, state.blue)?;
+    storage.set_u32(NVS_KEY_SERVO, state.servo)?;
+    Ok(())
+}
+
 fn main() -> anyhow::Result<()> {
     dotenv().ok();
     esp_idf_sys::link_patches();
@@ -23,33 +558,68 @@ fn main() -> anyhow::Result<()> {
     let sysloop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
 
+    let wifi_driver = WifiDriver::new(peripherals.modem, sysloop.clone(), Some(nvs.clone()))?;
+    let sta_netif = build_sta_netif(load_static_ip_config(&nvs))?;
+    let ap_netif = EspNetif::new(NetifStack::Ap)?;
     let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(peripherals.modem, sysloop.clone(), Some(nvs))?,
+        EspWifi::wrap_all(wifi_driver, sta_netif, ap_netif)?,
         sysloop,
     )?;
 
-    let ssid = env::var("WIFI_SSID")
-        .expect("WIFI_SSID not set in .env file").parse::<String<32>>().unwrap();
-    let password = env::var("WIFI_PASSWORD")
-        .expect("WIFI_PASSWORD not set in .env file").parse::<String<64>>().unwrap();
+    if wifi_enterprise_enabled() {
+        let eap = load_eap_credentials(&nvs)
+            .ok_or_else(|| anyhow!("WIFI_ENTERPRISE is set but EAP_IDENTITY/EAP_USERNAME/EAP_PASSWORD are missing"))?;
+        let ssid = env::var("WIFI_SSID")
+            .map_err(|_| anyhow!("WIFI_SSID not set in .env file"))?
+            .parse::<String<32>>()
+            .map_err(|_| anyhow!("SSID too long"))?;
 
-    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
-        esp_idf_svc::wifi::ClientConfiguration {
-            ssid,
-            password,
+        wifi.set_configuration(&Configuration::Client(esp_idf_svc::wifi::ClientConfiguration {
+            ssid: ssid.clone(),
+            auth_method: esp_idf_svc::wifi::AuthMethod::WPA2Enterprise,
             ..Default::default()
-        },
-    ))?;
+        }))?;
+        configure_eap(&eap)?;
+        wifi.start()?;
+        if !connect_with_retries(&mut wifi) {
+            bail!("Could not join enterprise network '{ssid}' after {WIFI_CONNECT_RETRIES} attempts");
+        }
+    } else {
+        let mut creds = load_wifi_credentials(&nvs)?.or_else(legacy_env_credentials);
+
+        loop {
+            let (ssid, password) = match creds.take() {
+                Some(creds) => creds,
+                None => {
+                    log::warn!("No stored Wi-Fi credentials found; starting provisioning access point");
+                    run_provisioning_portal(&mut wifi, &nvs)?
+                }
+            };
+
+            wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
+                esp_idf_svc::wifi::ClientConfiguration {
+                    ssid: ssid.clone(),
+                    password,
+                    ..Default::default()
+                },
+            ))?;
+            wifi.start()?;
+
+            if connect_with_retries(&mut wifi) {
+                break;
+            }
+
+            log::warn!(
+                "Could not join '{ssid}' after {WIFI_CONNECT_RETRIES} attempts; falling back to provisioning"
+            );
+        }
+    }
 
-    wifi.start()?;
-    wifi.connect()?;
     wifi.wait_netif_up()?;
 
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
     log::info!("IP info: {:?}", ip_info);
 
-    let socket = UdpSocket::bind(format!("{}:12345", ip_info.ip))?;
-
     let led_config = TimerConfig::default().frequency(25.kHz().into());
     let led_timer = LedcTimerDriver::new(peripherals.ledc.timer0, &led_config)?;
 
@@ -69,37 +639,325 @@ fn main() -> anyhow::Result<()> {
     let max_duty = red_channel.get_max_duty();
     let servo_max_duty = servo_channel.get_max_duty();
 
-    let mut buf = [0u8; 64];
-    let mut current_color = 0;
+    let device_id = env::var("DEVICE_ID").unwrap_or_else(|_| "esp32-power-trigger".to_string());
+    let cmd_topic = format!("devices/{device_id}/cmd");
+    let state_topic = format!("devices/{device_id}/state");
+
+    let broker_url =
+        env::var("MQTT_BROKER_URL").unwrap_or_else(|_| "mqtt://broker.local:1883".to_string());
+    let mqtt_config = MqttClientConfiguration {
+        client_id: Some(&device_id),
+        ..Default::default()
+    };
+
+    let (mut mqtt_client, mut mqtt_conn) = EspMqttClient::new(&broker_url, &mqtt_config)?;
+
+    let (tx, rx) = mpsc::channel::<Command>();
+    let http_tx = tx.clone();
+    std::thread::spawn(move || {
+        run_mqtt_listener(&mut mqtt_conn, tx);
+    });
+
+    mqtt_client.subscribe(&cmd_topic, QoS::AtLeastOnce)?;
+    log::info!("Subscribed to {cmd_topic}, publishing state on {state_topic}");
+
+    let mut http_server = EspHttpServer::new(&HttpServerConfiguration::default())?;
+    http_server.fn_handler("/", Method::Get, |request| {
+        let mut response = request.into_ok_response()?;
+        response.write_all(CONTROL_PAGE_HTML.as_bytes())?;
+        Ok(())
+    })?;
+    http_server.fn_handler("/set", Method::Get, move |request| {
+        let query = request.uri().split_once('?').map(|(_, q)| q).unwrap_or("");
+        match parse_set_query(query) {
+            Some(cmd) => {
+                http_tx.send(cmd).ok();
+                request.into_ok_response()?.write_all(b"ok")?;
+            }
+            None => {
+                request
+                    .into_response(400, Some("Bad Request"), &[])?
+                    .write_all(b"missing or invalid r/g/b/servo parameters")?;
+            }
+        }
+        Ok(())
+    })?;
+    log::info!("HTTP control page available at http://{}/", ip_info.ip);
+
+    let mut current_color: u32 = 0;
+    // Whether `current_color` still reflects the palette slot actually showing;
+    // an explicit RGB command moves the light off the palette entirely.
+    let mut color_index_valid = true;
+    let mut last_angle: u8 = 0;
+    let mut channel_state = ChannelState {
+        red: 0,
+        green: 0,
+        blue: 0,
+        servo: 0,
+    };
 
+    if let Some((stored_color, stored_color_valid, stored_state, stored_angle)) = load_device_state(&nvs) {
+        log::info!("Restoring saved state: color_index={stored_color}, angle={stored_angle}");
+        let restored = set_color(
+            &mut red_channel,
+            &mut green_channel,
+            &mut blue_channel,
+            stored_state.red,
+            stored_state.green,
+            stored_state.blue,
+        )
+        .and_then(|()| Ok(servo_channel.set_duty(stored_state.servo)?));
+
+        match restored {
+            Ok(()) => {
+                current_color = stored_color;
+                color_index_valid = stored_color_valid;
+                last_angle = stored_angle;
+                channel_state = stored_state;
+            }
+            Err(e) => {
+                log::warn!("Failed to restore saved device state: {e:?}; using defaults");
+                // A partial apply above may have already driven the RGB channels (or
+                // the servo) to the stored duty before the failure. Force the
+                // hardware back to the defaults we're falling back to in software,
+                // so `channel_state` doesn't lie about what's actually showing.
+                set_color(&mut red_channel, &mut green_channel, &mut blue_channel, 0, 0, 0).ok();
+                servo_channel.set_duty(0).ok();
+            }
+        }
+    }
+
+    let mut pending = None;
     loop {
-        match socket.recv_from(&mut buf) {
-            Ok((size, _)) => {
-                if let Ok(data) = std::str::from_utf8(&buf[..size]) {
-                    if data.starts_with("TOGGLE") {
-                        current_color = (current_color + 1) % 6;
-                    } else if let Ok(angle) = u8::from_str(data) {
-                        let intensity = (angle as u32 * max_duty) / 180;
-                        match current_color {
-                            0 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, intensity, 0, 0)?,
-                            1 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, 0, intensity, 0)?,
-                            2 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, 0, 0, intensity)?,
-                            3 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, intensity, intensity, 0)?,
-                            4 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, intensity, 0, intensity)?,
-                            5 => set_color(&mut red_channel, &mut green_channel, &mut blue_channel, 0, intensity, intensity)?,
-                            _ => {}
-                        }
-
-                        let servo_duty = map_angle_to_duty(angle, servo_max_duty);
-                        servo_channel.set_duty(servo_duty)?;
-                    }
+        let cmd = match pending.take() {
+            Some(cmd) => cmd,
+            None => match rx.recv() {
+                Ok(cmd) => cmd,
+                Err(_) => break,
+            },
+        };
+
+        pending = match cmd {
+            Command::Toggle => {
+                current_color = (current_color + 1) % 6;
+                color_index_valid = true;
+                None
+            }
+            Command::Angle(angle) => {
+                color_index_valid = true;
+                last_angle = angle;
+                let intensity = (angle as u32 * max_duty) / 180;
+                let (target_r, target_g, target_b) = palette_targets(current_color, intensity);
+                fade_color(
+                    &mut red_channel,
+                    &mut green_channel,
+                    &mut blue_channel,
+                    &mut channel_state,
+                    (target_r, target_g, target_b),
+                    FADE_DURATION_MS,
+                    &rx,
+                )
+                .or_else(|| {
+                    let servo_duty = map_angle_to_duty(angle, servo_max_duty);
+                    fade_to(
+                        &mut servo_channel,
+                        &mut channel_state.servo,
+                        servo_duty,
+                        FADE_DURATION_MS,
+                        &rx,
+                    )
+                })
+            }
+            Command::Explicit { r, g, b, servo } => {
+                color_index_valid = false;
+                let scale = |v: u8| (v as u32 * max_duty) / 255;
+                let interrupted = fade_color(
+                    &mut red_channel,
+                    &mut green_channel,
+                    &mut blue_channel,
+                    &mut channel_state,
+                    (scale(r), scale(g), scale(b)),
+                    FADE_DURATION_MS,
+                    &rx,
+                );
+                interrupted.or_else(|| {
+                    servo.and_then(|angle| {
+                        last_angle = angle;
+                        fade_to(
+                            &mut servo_channel,
+                            &mut channel_state.servo,
+                            map_angle_to_duty(angle, servo_max_duty),
+                            FADE_DURATION_MS,
+                            &rx,
+                        )
+                    })
+                })
+            }
+        };
+
+        if pending.is_some() {
+            // A new command interrupted the fade; retarget immediately
+            // without publishing stale state.
+            continue;
+        }
+
+        let state = StatePayload {
+            color_index: color_index_valid.then_some(current_color),
+            servo_angle: last_angle,
+        };
+        if let Ok(payload) = serde_json::to_vec(&state) {
+            if let Err(e) = mqtt_client.publish(&state_topic, QoS::AtLeastOnce, true, &payload) {
+                log::warn!("Failed to publish state: {e:?}");
+            }
+        }
+        if let Err(e) = save_device_state(&nvs, current_color, color_index_valid, &channel_state, last_angle) {
+            log::warn!("Failed to persist device state: {e:?}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Interpolates `channel`'s duty from `current` to `target` over
+/// `duration_ms`, in `FADE_STEP_MS` increments. Returns early with the
+/// interrupting command if one arrives on `rx` mid-fade, leaving `current`
+/// at the duty reached so far.
+fn fade_to(
+    channel: &mut LedcDriver<'_>,
+    current: &mut u32,
+    target: u32,
+    duration_ms: u32,
+    rx: &mpsc::Receiver<Command>,
+) -> Option<Command> {
+    let steps = (duration_ms / FADE_STEP_MS).max(1);
+    let start = *current as i64;
+    let delta = target as i64 - start;
+
+    for step in 1..=steps {
+        if let Ok(cmd) = rx.try_recv() {
+            return Some(cmd);
+        }
+        let duty = (start + delta * step as i64 / steps as i64) as u32;
+        channel.set_duty(duty).ok();
+        *current = duty;
+        FreeRtos::delay_ms(FADE_STEP_MS);
+    }
+
+    None
+}
+
+/// Fades the red/green/blue channels to `target` together over `duration_ms`.
+fn fade_color(
+    red: &mut LedcDriver<'_>,
+    green: &mut LedcDriver<'_>,
+    blue: &mut LedcDriver<'_>,
+    state: &mut ChannelState,
+    target: (u32, u32, u32),
+    duration_ms: u32,
+    rx: &mpsc::Receiver<Command>,
+) -> Option<Command> {
+    let steps = (duration_ms / FADE_STEP_MS).max(1);
+    let start = (state.red as i64, state.green as i64, state.blue as i64);
+    let delta = (
+        target.0 as i64 - start.0,
+        target.1 as i64 - start.1,
+        target.2 as i64 - start.2,
+    );
+
+    for step in 1..=steps {
+        if let Ok(cmd) = rx.try_recv() {
+            return Some(cmd);
+        }
+        let r = (start.0 + delta.0 * step as i64 / steps as i64) as u32;
+        let g = (start.1 + delta.1 * step as i64 / steps as i64) as u32;
+        let b = (start.2 + delta.2 * step as i64 / steps as i64) as u32;
+        set_color(red, green, blue, r, g, b).ok();
+        state.red = r;
+        state.green = g;
+        state.blue = b;
+        FreeRtos::delay_ms(FADE_STEP_MS);
+    }
+
+    None
+}
+
+/// Parses the `r`, `g`, `b` and optional `servo` parameters out of a
+/// `/set` query string, e.g. `r=255&g=0&b=128&servo=90`.
+fn parse_set_query(query: &str) -> Option<Command> {
+    let mut r = None;
+    let mut g = None;
+    let mut b = None;
+    let mut servo = None;
+
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=')?;
+        match key {
+            "r" => r = value.parse::<u8>().ok(),
+            "g" => g = value.parse::<u8>().ok(),
+            "b" => b = value.parse::<u8>().ok(),
+            "servo" => servo = value.parse::<u8>().ok(),
+            _ => {}
+        }
+    }
+
+    Some(Command::Explicit {
+        r: r?,
+        g: g?,
+        b: b?,
+        servo,
+    })
+}
+
+/// Resolves a legacy palette index + intensity into an RGB duty target.
+fn palette_targets(color_index: u32, intensity: u32) -> (u32, u32, u32) {
+    match color_index {
+        0 => (intensity, 0, 0),
+        1 => (0, intensity, 0),
+        2 => (0, 0, intensity),
+        3 => (intensity, intensity, 0),
+        4 => (intensity, 0, intensity),
+        5 => (0, intensity, intensity),
+        _ => (0, 0, 0),
+    }
+}
+
+/// Drains MQTT connection events and forwards parsed commands to the main loop.
+fn run_mqtt_listener(conn: &mut EspMqttConnection, tx: mpsc::Sender<Command>) {
+    while let Ok(event) = conn.next() {
+        use esp_idf_svc::mqtt::client::EventPayload;
+        if let EventPayload::Received { data, .. } = event.payload() {
+            if let Some(cmd) = parse_command(data) {
+                if tx.send(cmd).is_err() {
+                    break;
                 }
             }
-            Err(e) => log::error!("Error receiving data: {:?}", e),
         }
     }
 }
 
+/// Parses an incoming MQTT payload as either the legacy `TOGGLE`/`0-180` format
+/// or the `{"r":..,"g":..,"b":..,"servo":..}` JSON format. As with the HTTP
+/// `/set` handler, `r`/`g`/`b` must be given together; a payload that sets
+/// only `servo` is ignored rather than snapping the color to black.
+fn parse_command(data: &[u8]) -> Option<Command> {
+    let text = std::str::from_utf8(data).ok()?;
+    if text.starts_with("TOGGLE") {
+        return Some(Command::Toggle);
+    }
+    if let Ok(angle) = u8::from_str(text.trim()) {
+        return Some(Command::Angle(angle));
+    }
+    if let Ok(json) = serde_json::from_str::<JsonCommand>(text) {
+        return Some(Command::Explicit {
+            r: json.r?,
+            g: json.g?,
+            b: json.b?,
+            servo: json.servo,
+        });
+    }
+    None
+}
+
 fn set_color(
     red: &mut LedcDriver<'_>,
     green: &mut LedcDriver<'_>,
@@ -121,4 +979,4 @@ fn map_angle_to_duty(angle: u8, max_duty: u32) -> u32 {
     let duty_range = max_duty - min_duty;
 
     min_duty + (angle as u32 * duty_range) / 180
-}
\ No newline at end of file
+}